@@ -1,12 +1,67 @@
-use cargo_metadata::{Metadata, MetadataCommand, Package};
+use cargo_metadata::{Metadata, MetadataCommand, Package, Target};
 use clap::{Parser, Subcommand};
 use ocipkg::{error::*, ImageName};
+use serde::{Deserialize, Serialize};
 use std::{
+    collections::HashMap,
     fs,
     path::{Path, PathBuf},
     process::Command,
 };
 
+#[path = "common.rs"]
+mod common;
+use common::{host_arch, host_os, human_size, os_arch_from_triple, verify_archive, Compression};
+
+/// Fingerprint of a target's packed inputs, image name, compression and git
+/// revision, borrowed from cargo's workcache/fingerprint idea. If the
+/// recomputed fingerprint matches the one stored next to a `.tar`, packing is
+/// skipped. The `dirty` and branch annotations are not part of it.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+struct Fingerprint {
+    /// sha256 digest of each input file, in the order they're packed
+    inputs: Vec<(PathBuf, String)>,
+    image_name: String,
+    compression: String,
+    revision: String,
+}
+
+fn hash_file(path: &Path) -> Result<String> {
+    use sha2::{Digest, Sha256};
+    let data = fs::read(path)?;
+    Ok(format!("{:x}", Sha256::digest(data)))
+}
+
+fn compute_fingerprint(
+    targets: &[PathBuf],
+    image_name: &ImageName,
+    compression: Compression,
+    revision: &str,
+) -> Result<Fingerprint> {
+    let inputs = targets
+        .iter()
+        .map(|path| Ok((path.clone(), hash_file(path)?)))
+        .collect::<Result<_>>()?;
+    Ok(Fingerprint {
+        inputs,
+        image_name: image_name.to_string(),
+        compression: format!("{compression:?}"),
+        revision: revision.to_string(),
+    })
+}
+
+fn fingerprint_path(dest: &Path) -> PathBuf {
+    let mut path = dest.as_os_str().to_owned();
+    path.push(".fingerprint");
+    PathBuf::from(path)
+}
+
+/// A missing or unparsable fingerprint file always forces a rebuild.
+fn read_fingerprint(path: &Path) -> Option<Fingerprint> {
+    let data = fs::read(path).ok()?;
+    serde_json::from_slice(&data).ok()
+}
+
 #[derive(Parser, Debug)]
 #[clap(version)]
 enum Opt {
@@ -26,6 +81,23 @@ enum Ocipkg {
         /// Name of container
         #[clap(short = 't', long = "tag")]
         tag: Option<String>,
+        /// Print what would be packed without writing the archive
+        #[clap(long)]
+        list: bool,
+        /// Layer compression to use
+        #[clap(long, arg_enum, default_value = "gzip")]
+        compression: Compression,
+        /// Skip re-opening each produced archive to check its digests
+        #[clap(long)]
+        no_verify: bool,
+        /// Allow packing with uncommitted changes in the working tree
+        #[clap(long)]
+        allow_dirty: bool,
+        /// Target triple to build for (e.g. x86_64-unknown-linux-gnu). May be
+        /// given multiple times to assemble a multi-architecture image
+        /// index. Defaults to the host triple.
+        #[clap(long = "target")]
+        platforms: Vec<String>,
     },
 }
 
@@ -61,7 +133,17 @@ fn get_package(metadata: &Metadata, package_name: Option<String>) -> Package {
 }
 
 fn get_build_dir(metadata: &Metadata, release: bool) -> PathBuf {
+    get_build_dir_for(metadata, release, None)
+}
+
+/// Same as `get_build_dir`, but for a cross-compiled `--target <triple>`,
+/// where cargo nests the profile directory one level deeper.
+fn get_build_dir_for(metadata: &Metadata, release: bool, triple: Option<&str>) -> PathBuf {
     let target_dir = metadata.target_directory.clone().into_std_path_buf();
+    let target_dir = match triple {
+        Some(triple) => target_dir.join(triple),
+        None => target_dir,
+    };
     if release {
         target_dir.join("release")
     } else {
@@ -69,20 +151,77 @@ fn get_build_dir(metadata: &Metadata, release: bool) -> PathBuf {
     }
 }
 
-fn get_revision(manifest_path: &Path) -> String {
+/// VCS provenance embedded into the image's annotations, analogous to
+/// cargo's `.cargo_vcs_info.json`.
+struct VcsInfo {
+    revision: String,
+    dirty: bool,
+    branch: Option<String>,
+}
+
+fn vcs_info(manifest_path: &Path) -> VcsInfo {
     let repo = git2::Repository::discover(manifest_path).expect("Git repository not found");
-    // This means repository is not in rebase or merge process,
-    // do not means "not dirty"
-    if repo.state() != git2::RepositoryState::Clean {
-        panic!("Git repository is not clean: {}", manifest_path.display())
-    }
-    let rev = repo
+    let mut opts = git2::StatusOptions::new();
+    opts.include_untracked(true);
+    // `repo.state()` only tells us we're not mid-rebase/-merge, not that the
+    // working tree is clean, so also check for actual uncommitted changes.
+    let dirty = repo.state() != git2::RepositoryState::Clean
+        || repo
+            .statuses(Some(&mut opts))
+            .map(|statuses| !statuses.is_empty())
+            .unwrap_or(true);
+    let revision = repo
         .revparse_single("HEAD")
-        .expect("git rev-parse returns unexptected value");
-    rev.id().to_string()
+        .expect("git rev-parse returns unexptected value")
+        .id()
+        .to_string();
+    let branch = repo
+        .head()
+        .ok()
+        .and_then(|head| head.shorthand().map(str::to_string));
+    VcsInfo {
+        revision,
+        dirty,
+        branch,
+    }
+}
+
+/// Abort with an actionable error if the tree is dirty and `--allow-dirty`
+/// wasn't passed; otherwise just warn and continue.
+fn check_dirty(vcs: &VcsInfo, manifest_path: &Path, allow_dirty: bool) {
+    if !vcs.dirty {
+        return;
+    }
+    if allow_dirty {
+        log::warn!(
+            "Git repository has uncommitted changes: {}",
+            manifest_path.display()
+        );
+    } else {
+        panic!(
+            "Git repository has uncommitted changes: {}\n\
+             Commit or stash them, or pass --allow-dirty to pack anyway.",
+            manifest_path.display()
+        );
+    }
+}
+
+/// Build the image annotations carrying VCS provenance, analogous to
+/// cargo's `.cargo_vcs_info.json`.
+fn vcs_annotations(vcs: &VcsInfo) -> HashMap<String, String> {
+    let mut annotations = HashMap::new();
+    annotations.insert(
+        "org.opencontainers.image.revision".to_string(),
+        vcs.revision.clone(),
+    );
+    annotations.insert("io.ocipkg.dirty".to_string(), vcs.dirty.to_string());
+    if let Some(branch) = &vcs.branch {
+        annotations.insert("io.ocipkg.branch".to_string(), branch.clone());
+    }
+    annotations
 }
 
-fn generate_image_name(package: &Package) -> ImageName {
+fn generate_image_name(package: &Package, revision: &str) -> ImageName {
     use serde_json::Value;
     match &package.metadata {
         Value::Object(obj) => {
@@ -95,9 +234,7 @@ fn generate_image_name(package: &Package) -> ImageName {
                         .get("registry")
                         .expect("`package.metadata.ocipkg` does not have `registry`")
                     {
-                        let rev = get_revision(package.manifest_path.as_std_path());
-
-                        ImageName::parse(&format!("{}:{}", registry, rev))
+                        ImageName::parse(&format!("{}:{}", registry, revision))
                             .expect("Invalud registry URL")
                     } else {
                         panic!("`package.metadata.ocipkg.registry` must be a string")
@@ -112,6 +249,29 @@ fn generate_image_name(package: &Package) -> ImageName {
     }
 }
 
+/// The built artifact(s) for a package target's `crate-types`, e.g. the
+/// `.a`/`.lib` and `.so`/`.dylib`/`.dll` cargo produces for `staticlib` and
+/// `cdylib`, under `build_dir`.
+fn target_paths(target: &Target, build_dir: &Path, os: &str) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    for ty in &target.crate_types {
+        match ty.as_str() {
+            "staticlib" => paths.push(build_dir.join(if os == "windows" {
+                format!("{}.lib", target.name.replace('-', "_"))
+            } else {
+                format!("lib{}.a", target.name.replace('-', "_"))
+            })),
+            "cdylib" => paths.push(build_dir.join(match os {
+                "windows" => format!("{}.dll", target.name.replace('-', "_")),
+                "darwin" => format!("lib{}.dylib", target.name.replace('-', "_")),
+                _ => format!("lib{}.so", target.name.replace('-', "_")),
+            })),
+            _ => {}
+        }
+    }
+    paths
+}
+
 fn main() -> Result<()> {
     env_logger::Builder::new()
         .filter_level(log::LevelFilter::Info)
@@ -123,55 +283,199 @@ fn main() -> Result<()> {
             package_name,
             release,
             tag,
+            list,
+            compression,
+            no_verify,
+            allow_dirty,
+            platforms,
         }) => {
             let metadata = get_metadata();
             let package = get_package(&metadata, package_name);
-            let build_dir = get_build_dir(&metadata, release);
-            let image_name = if let Some(ref tag) = tag {
-                ImageName::parse(tag)?
+
+            // Default to the host triple: a single, untagged build using the
+            // existing `target/{debug,release}` layout.
+            let platforms: Vec<Option<String>> = if platforms.is_empty() {
+                vec![None]
             } else {
-                generate_image_name(&package)
+                platforms.into_iter().map(Some).collect()
             };
 
-            let mut cmd = Command::new("cargo");
-            cmd.arg("build");
-            if release {
-                cmd.arg("--release");
+            let mut builds = Vec::new();
+            for platform in &platforms {
+                let mut cmd = Command::new("cargo");
+                cmd.arg("build");
+                if release {
+                    cmd.arg("--release");
+                }
+                if let Some(triple) = platform {
+                    cmd.args(["--target", triple]);
+                }
+                cmd.args(["--manifest-path", package.manifest_path.as_str()])
+                    .status()?;
+                let build_dir = get_build_dir_for(&metadata, release, platform.as_deref());
+                builds.push((platform.clone(), build_dir));
             }
-            cmd.args(["--manifest-path", package.manifest_path.as_str()])
-                .status()?;
 
-            for target in package.targets {
-                let mut targets = Vec::new();
-                for ty in target.crate_types {
-                    // FIXME support non-Linux OS
-                    match ty.as_str() {
-                        "staticlib" => {
-                            targets.push(
-                                build_dir.join(format!("lib{}.a", target.name.replace('-', "_"))),
-                            );
-                        }
-                        "cdylib" => {
-                            targets.push(
-                                build_dir.join(format!("lib{}.so", target.name.replace('-', "_"))),
-                            );
+            if list {
+                let mut listed = Vec::new();
+                for target in &package.targets {
+                    for (platform, build_dir) in &builds {
+                        let (os, _arch) = match platform {
+                            Some(triple) => os_arch_from_triple(triple),
+                            None => (host_os(), host_arch()),
+                        };
+                        for path in target_paths(target, build_dir, &os) {
+                            let size = fs::metadata(&path)?.len();
+                            println!("{} ({})", path.display(), human_size(size));
+                            listed.push(size);
                         }
-                        _ => {}
                     }
                 }
+                let total = listed.iter().sum();
+                println!("total {} files, {}", listed.len(), human_size(total));
+                return Ok(());
+            }
+
+            // Only consulted once we know packing will actually write
+            // something: a dirty tree or a missing registry tag shouldn't
+            // block a `--list` preview.
+            let manifest_path = package.manifest_path.as_std_path();
+            let vcs = vcs_info(manifest_path);
+            check_dirty(&vcs, manifest_path, allow_dirty);
+            let image_name = if let Some(ref tag) = tag {
+                ImageName::parse(tag)?
+            } else {
+                generate_image_name(&package, &vcs.revision)
+            };
+
+            for target in package.targets {
+                // Every platform's tar for this package target, used to
+                // assemble a multi-architecture image index when there is
+                // more than one.
+                let mut platform_tars = Vec::new();
+
+                for (platform, build_dir) in &builds {
+                    let (os, arch) = match platform {
+                        Some(triple) => os_arch_from_triple(triple),
+                        None => (host_os(), host_arch()),
+                    };
+
+                    let targets = target_paths(&target, build_dir, &os);
+                    if targets.is_empty() {
+                        panic!("No target exists for packing. Only staticlib or cdylib are suppoted.");
+                    }
+
+                    let dest = match platform {
+                        Some(triple) => build_dir.join(format!("{}-{triple}.tar", target.name)),
+                        None => build_dir.join(format!("{}.tar", target.name)),
+                    };
+                    let fingerprint =
+                        compute_fingerprint(&targets, &image_name, compression, &vcs.revision)?;
+                    let fingerprint_path = fingerprint_path(&dest);
+                    if dest.exists()
+                        && read_fingerprint(&fingerprint_path).as_ref() == Some(&fingerprint)
+                    {
+                        log::info!("{} is up to date, skipping pack", dest.display());
+                        platform_tars.push(dest);
+                        continue;
+                    }
+
+                    let f = fs::File::create(&dest)?;
+                    let mut b = ocipkg::image::Builder::new(f);
+                    b.set_compression(compression.into());
+                    b.set_name(&image_name);
+                    b.set_annotations(vcs_annotations(&vcs));
+                    b.set_platform(&os, &arch);
+                    b.append_files(&targets)?;
+                    let _output = b.into_inner()?;
+
+                    if !no_verify {
+                        verify_archive(&dest)?;
+                        log::info!("verified {}", dest.display());
+                    }
+
+                    fs::write(&fingerprint_path, serde_json::to_vec(&fingerprint)?)?;
+                    platform_tars.push(dest);
+                }
 
-                if targets.is_empty() {
-                    panic!("No target exists for packing. Only staticlib or cdylib are suppoted.");
+                if platform_tars.len() < 2 {
+                    continue;
                 }
 
-                let dest = build_dir.join(format!("{}.tar", target.name));
-                let f = fs::File::create(dest)?;
-                let mut b = ocipkg::image::Builder::new(f);
-                b.set_name(&image_name);
-                b.append_files(&targets)?;
-                let _output = b.into_inner()?;
+                let index_dest = get_build_dir(&metadata, release).join(format!("{}.tar", target.name));
+                // Only the per-triple build dirs are guaranteed to exist: cargo
+                // never creates `target/{debug,release}` itself when every
+                // build ran with an explicit `--target`.
+                fs::create_dir_all(
+                    index_dest
+                        .parent()
+                        .expect("index_dest always has a parent"),
+                )?;
+                ocipkg::image::build_index(&platform_tars, &index_dest)?;
+                log::info!(
+                    "assembled {}-platform image index at {}",
+                    platform_tars.len(),
+                    index_dest.display()
+                );
             }
         }
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata_with_target_dir(target_dir: &str) -> Metadata {
+        let json = serde_json::json!({
+            "packages": [],
+            "workspace_members": [],
+            "resolve": null,
+            "target_directory": target_dir,
+            "workspace_root": target_dir,
+            "version": 1,
+            "metadata": null,
+        });
+        serde_json::from_value(json).expect("minimal cargo-metadata JSON should parse")
+    }
+
+    #[test]
+    fn get_build_dir_for_nests_under_the_target_triple() {
+        let metadata = metadata_with_target_dir("/repo/target");
+        assert_eq!(
+            get_build_dir_for(&metadata, false, None),
+            PathBuf::from("/repo/target/debug")
+        );
+        assert_eq!(
+            get_build_dir_for(&metadata, true, None),
+            PathBuf::from("/repo/target/release")
+        );
+        assert_eq!(
+            get_build_dir_for(&metadata, true, Some("x86_64-unknown-linux-gnu")),
+            PathBuf::from("/repo/target/x86_64-unknown-linux-gnu/release")
+        );
+    }
+
+    #[test]
+    fn fingerprint_is_stable_for_identical_inputs_and_changes_with_content() {
+        let dir = std::env::temp_dir().join(format!(
+            "ocipkg-fingerprint-test-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("lib.a");
+        fs::write(&file, b"static lib contents").unwrap();
+        let image_name = ImageName::parse("localhost/test:latest").unwrap();
+
+        let a = compute_fingerprint(&[file.clone()], &image_name, Compression::Gzip, "abc123").unwrap();
+        let b = compute_fingerprint(&[file.clone()], &image_name, Compression::Gzip, "abc123").unwrap();
+        assert_eq!(a, b);
+
+        fs::write(&file, b"different contents").unwrap();
+        let c = compute_fingerprint(&[file], &image_name, Compression::Gzip, "abc123").unwrap();
+        assert_ne!(a, c);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,214 @@
+//! Helpers shared by the `ocipkg` and `cargo-ocipkg` binaries. Included via
+//! `#[path = "common.rs"] mod common;` rather than a library crate, since
+//! these are small, binary-local utilities rather than public API.
+
+use oci_spec::image::{ImageIndex, ImageManifest};
+use ocipkg::error::*;
+use std::{collections::HashMap, fs, io::Read, path::Path};
+
+/// Layer compression to use when packing, mirrored from
+/// `ocipkg::image::Compression`.
+#[derive(Debug, Clone, Copy, clap::ArgEnum)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl From<Compression> for ocipkg::image::Compression {
+    fn from(c: Compression) -> Self {
+        match c {
+            Compression::None => ocipkg::image::Compression::None,
+            Compression::Gzip => ocipkg::image::Compression::Gzip,
+            Compression::Zstd => ocipkg::image::Compression::Zstd,
+        }
+    }
+}
+
+/// Format a byte count the way `cargo package --list` does, e.g. `1.2MiB`.
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes}B")
+    } else {
+        format!("{size:.1}{}", UNITS[unit])
+    }
+}
+
+/// Read every entry of an oci-archive tar into memory, keyed by its path
+/// inside the archive (e.g. `index.json`, `blobs/sha256/<digest>`).
+pub fn read_archive(input: &Path) -> Result<HashMap<String, Vec<u8>>> {
+    let mut blobs = HashMap::new();
+    let mut archive = tar::Archive::new(fs::File::open(input)?);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().into_owned();
+        let mut buf = Vec::new();
+        entry.read_to_end(&mut buf)?;
+        blobs.insert(path, buf);
+    }
+    Ok(blobs)
+}
+
+pub fn blob_path(digest: &str) -> String {
+    format!("blobs/{}", digest.replace(':', "/"))
+}
+
+pub fn sha256_digest(data: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+    format!("sha256:{:x}", Sha256::digest(data))
+}
+
+/// Size of a layer's content once its media type's compression is undone.
+/// `application/vnd.oci.image.layer.v1.tar` layers are already uncompressed.
+pub fn uncompressed_size(media_type: &str, data: &[u8]) -> Result<u64> {
+    if media_type.ends_with("+gzip") {
+        use flate2::read::GzDecoder;
+        let mut decoder = GzDecoder::new(data);
+        let mut count = 0u64;
+        let mut buf = [0u8; 64 * 1024];
+        loop {
+            let n = decoder.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            count += n as u64;
+        }
+        Ok(count)
+    } else if media_type.ends_with("+zstd") {
+        Ok(zstd::stream::decode_all(data)?.len() as u64)
+    } else {
+        Ok(data.len() as u64)
+    }
+}
+
+/// Re-open a just-written archive through the same read path as `Inspect`
+/// and recompute every layer and config digest, failing loudly if anything
+/// doesn't match the descriptors recorded in the manifest.
+pub fn verify_archive(input: &Path) -> Result<()> {
+    let blobs = read_archive(input)?;
+    let index: ImageIndex = serde_json::from_slice(
+        blobs
+            .get("index.json")
+            .expect("oci-archive is missing index.json"),
+    )?;
+
+    for manifest_desc in index.manifests() {
+        let manifest_blob = blobs
+            .get(&blob_path(manifest_desc.digest()))
+            .unwrap_or_else(|| panic!("manifest blob {} missing from archive", manifest_desc.digest()));
+        let actual = sha256_digest(manifest_blob);
+        if &actual != manifest_desc.digest() {
+            panic!(
+                "manifest digest mismatch: expected {}, got {actual}",
+                manifest_desc.digest()
+            );
+        }
+        let manifest: ImageManifest = serde_json::from_slice(manifest_blob)?;
+
+        let config_blob = blobs
+            .get(&blob_path(manifest.config().digest()))
+            .unwrap_or_else(|| panic!("config blob {} missing from archive", manifest.config().digest()));
+        let actual = sha256_digest(config_blob);
+        if &actual != manifest.config().digest() {
+            panic!(
+                "config digest mismatch: expected {}, got {actual}",
+                manifest.config().digest()
+            );
+        }
+
+        for layer in manifest.layers() {
+            let layer_blob = blobs
+                .get(&blob_path(layer.digest()))
+                .unwrap_or_else(|| panic!("layer blob {} missing from archive", layer.digest()));
+            let actual = sha256_digest(layer_blob);
+            if &actual != layer.digest() {
+                panic!(
+                    "layer digest mismatch: expected {}, got {actual}",
+                    layer.digest()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Split a target triple into the `os` and `architecture` values used by an
+/// OCI image config, e.g. `x86_64-unknown-linux-gnu` -> `(linux, amd64)`.
+pub fn os_arch_from_triple(triple: &str) -> (String, String) {
+    let arch = match triple.split('-').next().unwrap_or(triple) {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "i686" => "386",
+        other => other,
+    };
+    let os = if triple.contains("windows") {
+        "windows"
+    } else if triple.contains("apple") {
+        "darwin"
+    } else {
+        "linux"
+    };
+    (os.to_string(), arch.to_string())
+}
+
+/// `os`/`architecture` of the host, in the same vocabulary as
+/// `os_arch_from_triple`, used when no `--target` is given.
+pub fn host_os() -> String {
+    match std::env::consts::OS {
+        "macos" => "darwin",
+        other => other,
+    }
+    .to_string()
+}
+
+pub fn host_arch() -> String {
+    match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "x86" => "386",
+        other => other,
+    }
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn human_size_formats_binary_units() {
+        assert_eq!(human_size(0), "0B");
+        assert_eq!(human_size(512), "512B");
+        assert_eq!(human_size(1024), "1.0KiB");
+        assert_eq!(human_size(1536), "1.5KiB");
+        assert_eq!(human_size(1024 * 1024), "1.0MiB");
+        assert_eq!(human_size(5 * 1024 * 1024 * 1024), "5.0GiB");
+    }
+
+    #[test]
+    fn os_arch_from_triple_maps_known_triples() {
+        assert_eq!(
+            os_arch_from_triple("x86_64-unknown-linux-gnu"),
+            ("linux".to_string(), "amd64".to_string())
+        );
+        assert_eq!(
+            os_arch_from_triple("aarch64-apple-darwin"),
+            ("darwin".to_string(), "arm64".to_string())
+        );
+        assert_eq!(
+            os_arch_from_triple("x86_64-pc-windows-msvc"),
+            ("windows".to_string(), "amd64".to_string())
+        );
+        assert_eq!(
+            os_arch_from_triple("i686-unknown-linux-gnu"),
+            ("linux".to_string(), "386".to_string())
+        );
+    }
+}
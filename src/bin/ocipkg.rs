@@ -1,6 +1,14 @@
 use clap::Parser;
+use oci_spec::image::{ImageConfiguration, ImageIndex, ImageManifest};
 use ocipkg::error::*;
-use std::{fs, path::PathBuf};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+#[path = "common.rs"]
+mod common;
+use common::{blob_path, human_size, read_archive, uncompressed_size, verify_archive, Compression};
 
 #[derive(Debug, Parser)]
 #[clap(version)]
@@ -18,6 +26,18 @@ enum Opt {
         /// Name of container, use UUID v4 hyphenated if not set.
         #[clap(short = 't', long = "tag")]
         tag: Option<String>,
+
+        /// Print what would be packed without writing the archive
+        #[clap(long)]
+        list: bool,
+
+        /// Layer compression to use
+        #[clap(long, arg_enum, default_value = "gzip")]
+        compression: Compression,
+
+        /// Re-open the produced archive and check every digest matches
+        #[clap(long)]
+        verify: bool,
     },
 
     /// Load and expand container local cache
@@ -30,6 +50,11 @@ enum Opt {
     /// Get and save in local storage
     Get {
         image_name: String,
+
+        /// Platform to resolve from a multi-architecture image index, e.g.
+        /// `linux/amd64`. Defaults to the host platform.
+        #[clap(long)]
+        platform: Option<String>,
     },
 
     /// Push oci-archive to registry
@@ -39,14 +64,39 @@ enum Opt {
         input: PathBuf,
     },
 
+    /// Dump manifest, config, and layer metadata of an oci-archive
+    Inspect {
+        /// Input oci-archive
+        #[clap(parse(from_os_str))]
+        input: PathBuf,
+    },
+
     /// Get image directory to be used by ocipkg for given container name
     ImageDirectory {
         image_name: String,
+
+        /// Platform to resolve from a multi-architecture image index, e.g.
+        /// `linux/amd64`. Defaults to the host platform.
+        #[clap(long)]
+        platform: Option<String>,
     },
 
     List,
 }
 
+/// Parse a `--platform os/arch` value, defaulting to the host platform.
+fn resolve_platform(platform: Option<String>) -> (String, String) {
+    match platform {
+        Some(platform) => {
+            let (os, arch) = platform
+                .split_once('/')
+                .expect("--platform must be formatted as os/arch, e.g. linux/amd64");
+            (os.to_string(), arch.to_string())
+        }
+        None => (common::host_os(), common::host_arch()),
+    }
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()> {
     env_logger::Builder::new()
@@ -59,37 +109,71 @@ async fn main() -> Result<()> {
             input_directory,
             output,
             tag,
+            list,
+            compression,
+            verify,
         } => {
+            if list {
+                let entries = walk(&input_directory)?;
+                let total = entries.iter().map(|(_, size)| size).sum();
+                for (path, size) in &entries {
+                    println!("{} ({})", path.display(), human_size(*size));
+                }
+                println!("total {} files, {}", entries.len(), human_size(total));
+                return Ok(());
+            }
+
             let mut output = output;
             output.set_extension("tar");
             if output.exists() {
                 panic!("Output already exists: {}", output.display());
             }
-            let f = fs::File::create(output)?;
+            let f = fs::File::create(&output)?;
             let mut b = ocipkg::image::Builder::new(f);
+            b.set_compression(compression.into());
             if let Some(name) = tag {
                 b.set_name(&ocipkg::ImageName::parse(&name)?);
             }
             b.append_dir_all(&input_directory)?;
             let _output = b.into_inner()?;
+
+            if verify {
+                verify_archive(&output)?;
+                log::info!("verified {}", output.display());
+            }
         }
 
         Opt::Load { input } => {
             ocipkg::image::load(&input)?;
         }
 
-        Opt::Get { image_name } => {
+        Opt::Get {
+            image_name,
+            platform,
+        } => {
             let image_name = ocipkg::ImageName::parse(&image_name)?;
-            ocipkg::distribution::get_image(&image_name).await?;
+            let (os, arch) = resolve_platform(platform);
+            ocipkg::distribution::get_image_for_platform(&image_name, &os, &arch).await?;
         }
 
         Opt::Push { input } => {
             ocipkg::distribution::push_image(&input).await?;
         }
 
-        Opt::ImageDirectory { image_name } => {
+        Opt::Inspect { input } => {
+            inspect(&input)?;
+        }
+
+        Opt::ImageDirectory {
+            image_name,
+            platform,
+        } => {
             let image_name = ocipkg::ImageName::parse(&image_name)?;
-            println!("{}", ocipkg::local::image_dir(&image_name)?.display());
+            let (os, arch) = resolve_platform(platform);
+            println!(
+                "{}",
+                ocipkg::local::image_dir_for_platform(&image_name, &os, &arch)?.display()
+            );
         }
 
         Opt::List => {
@@ -100,4 +184,81 @@ async fn main() -> Result<()> {
         }
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Mirror the read-side of `cargo package`'s metadata handling: open an
+/// oci-archive and print the image name/tag, manifest digest, config, and a
+/// table of layers without loading anything into the local cache.
+fn inspect(input: &Path) -> Result<()> {
+    let blobs = read_archive(input)?;
+    let index: ImageIndex = serde_json::from_slice(
+        blobs
+            .get("index.json")
+            .expect("oci-archive is missing index.json"),
+    )?;
+
+    for manifest_desc in index.manifests() {
+        if let Some(annotations) = manifest_desc.annotations() {
+            if let Some(name) = annotations.get("org.opencontainers.image.ref.name") {
+                println!("name: {name}");
+            }
+        }
+        println!("manifest digest: {}", manifest_desc.digest());
+
+        let manifest: ImageManifest = serde_json::from_slice(
+            blobs
+                .get(&blob_path(manifest_desc.digest()))
+                .expect("manifest blob missing from archive"),
+        )?;
+
+        let config: ImageConfiguration = serde_json::from_slice(
+            blobs
+                .get(&blob_path(manifest.config().digest()))
+                .expect("config blob missing from archive"),
+        )?;
+        println!("config:\n{}", serde_json::to_string_pretty(&config)?);
+
+        println!(
+            "{:<12} {:<72} {:>10} {:>10}",
+            "MEDIA TYPE", "DIGEST", "SIZE", "UNCOMPRESSED"
+        );
+        for layer in manifest.layers() {
+            let layer_blob = blobs
+                .get(&blob_path(layer.digest()))
+                .expect("layer blob missing from archive");
+            let uncompressed = uncompressed_size(layer.media_type(), layer_blob)?;
+            println!(
+                "{:<12} {:<72} {:>10} {:>10}",
+                layer.media_type(),
+                layer.digest(),
+                human_size(layer.size() as u64),
+                human_size(uncompressed)
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Walk `dir` the same way `Builder::append_dir_all` does, collecting the path
+/// and size of every file that would be added to the archive.
+fn walk(dir: &Path) -> Result<Vec<(PathBuf, u64)>> {
+    let mut out = Vec::new();
+    walk_rec(dir, dir, &mut out)?;
+    out.sort();
+    Ok(out)
+}
+
+fn walk_rec(root: &Path, dir: &Path, out: &mut Vec<(PathBuf, u64)>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let meta = entry.metadata()?;
+        if meta.is_dir() {
+            walk_rec(root, &path, out)?;
+        } else {
+            let relative = path.strip_prefix(root).unwrap_or(&path).to_path_buf();
+            out.push((relative, meta.len()));
+        }
+    }
+    Ok(())
+}